@@ -1,25 +1,38 @@
 use std::mem;
-use std::mem::transmute;
+use std::mem::{transmute, MaybeUninit};
 use std::ptr;
+use std::cmp;
+use std::alloc::Layout;
 use std::marker::PhantomData;
 use std::ops::{Deref, DerefMut};
 use tagged_relative_pointer::TaggedRelativePointer;
 use allocators::{Allocator, DefaultHeap};
 
+/// Mirrors `std::collections::TryReserveError`, hand-rolled since that type
+/// can't be constructed outside of `std` on stable.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TryReserveError {
+    CapacityOverflow,
+    AllocError { layout: Layout }
+}
+
 pub trait Compact : Sized {
     fn is_still_compact(&self) -> bool;
     fn dynamic_size_bytes(&self) -> usize;
     fn total_size_bytes(&self) -> usize {
         self.dynamic_size_bytes() + mem::size_of::<Self>()
     }
-    unsafe fn compact_from(&mut self, source: &Self, new_dynamic_part: *mut u8);
+    /// Writes a compacted copy of `*source` into `*dest`, which may be raw,
+    /// uninitialized memory (e.g. a fresh arena slot) — so this must never
+    /// read `*dest` or rely on it holding a live value, only write into it.
+    unsafe fn compact(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8);
     unsafe fn behind(&mut self) -> *mut u8 {
         let behind_self = (self as *mut Self).offset(1);
         transmute(behind_self)
     }
     unsafe fn compact_behind_from(&mut self, source: &Self) {
         let behind_self = Self::behind(self);
-        self.compact_from(source, behind_self)
+        Self::compact(source as *const Self as *mut Self, self as *mut Self, behind_self)
     }
 }
 
@@ -80,32 +93,65 @@ impl<T, A: Allocator> CompactVec<T, A> {
         }
     }
 
-    fn double_buf(&mut self) {
-        let new_cap = if self.cap == 0 {1} else {self.cap * 2};
-        let mut vec = Vec::<T>::with_capacity(new_cap);
-        let new_ptr = vec.as_mut_ptr();
+    /// Ensures room for `additional` more elements, growing through `A`
+    /// (amortized doubling, capped at `max(len + additional, cap * 2)`)
+    /// instead of aborting the process on allocation failure.
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(), TryReserveError> {
+        if self.cap - self.len >= additional {
+            return Ok(());
+        }
+
+        let required_cap = self.len.checked_add(additional)
+            .ok_or(TryReserveError::CapacityOverflow)?;
+        let doubled_cap = self.cap.checked_mul(2)
+            .ok_or(TryReserveError::CapacityOverflow)?;
+        let new_cap = cmp::max(required_cap, doubled_cap);
+
+        let new_size_bytes = new_cap.checked_mul(mem::size_of::<T>())
+            .ok_or(TryReserveError::CapacityOverflow)?;
+        if new_size_bytes > isize::MAX as usize {
+            return Err(TryReserveError::CapacityOverflow);
+        }
+        let layout = Layout::array::<T>(new_cap)
+            .map_err(|_| TryReserveError::CapacityOverflow)?;
+
+        let new_ptr = A::allocate::<T>(new_cap);
+        if new_ptr.is_null() {
+            return Err(TryReserveError::AllocError { layout: layout });
+        }
 
         unsafe {
             ptr::copy_nonoverlapping(self.ptr.ptr(), new_ptr, self.len);
+            // The elements were just bitwise-moved into `new_ptr`, so only
+            // free the old buffer's raw bytes here — `maybe_drop` would also
+            // `drop_in_place` the old elements, destroying the very values
+            // that now live on (unmoved) in the new allocation.
+            if self.ptr.is_tagged() == FREE {
+                A::deallocate(self.ptr.mut_ptr(), self.cap);
+            }
         }
-        self.maybe_drop();
         self.ptr.set(new_ptr, FREE);
-        unsafe {
-            let p = self.ptr.ptr();
-            self.cap = new_cap;
-        }
+        self.cap = new_cap;
+        Ok(())
     }
 
-    pub fn push(&mut self, value: T) {
-        if self.len == self.cap {
-            self.double_buf();
+    pub fn try_push(&mut self, v: T) -> Result<(), (T, TryReserveError)> {
+        if let Err(err) = self.try_reserve(1) {
+            return Err((v, err));
         }
 
         unsafe {
             let end = self.as_mut_ptr().offset(self.len as isize);
-            ptr::write(end, value);
-            self.len += 1;
+            ptr::write(end, v);
         }
+        self.len += 1;
+        Ok(())
+    }
+
+    pub fn push(&mut self, value: T) {
+        self.try_push(value).unwrap_or_else(|(_, err)| {
+            panic!("CompactVec allocation failed: {:?}", err)
+        });
     }
 
     pub fn pop(&mut self) -> Option<T> {
@@ -120,9 +166,7 @@ impl<T, A: Allocator> CompactVec<T, A> {
     }
 
     pub fn insert(&mut self, index: usize, value: T) {
-        if self.len == self.cap {
-            self.double_buf();
-        }
+        self.try_reserve(1).expect("CompactVec allocation failed");
 
         unsafe {
             // infallible
@@ -134,6 +178,99 @@ impl<T, A: Allocator> CompactVec<T, A> {
             self.len += 1;
         }
     }
+
+    /// Sets the length without touching the backing storage. The caller must
+    /// ensure the first `new_len` elements are actually initialized, and that
+    /// `new_len <= self.cap`.
+    pub unsafe fn set_len(&mut self, new_len: usize) {
+        self.len = new_len;
+    }
+
+    pub fn remove(&mut self, index: usize) -> T {
+        assert!(index < self.len, "removal index (is {}) should be < len (is {})", index, self.len);
+
+        unsafe {
+            let p = self.as_mut_ptr().offset(index as isize);
+            let result = ptr::read(p);
+            ptr::copy(p.offset(1), p, self.len - index - 1);
+            self.len -= 1;
+            result
+        }
+    }
+
+    pub fn swap_remove(&mut self, index: usize) -> T {
+        assert!(index < self.len, "swap_remove index (is {}) should be < len (is {})", index, self.len);
+
+        unsafe {
+            let last = self.len - 1;
+            let p = self.as_mut_ptr();
+            let result = ptr::read(p.offset(index as isize));
+            ptr::copy(p.offset(last as isize), p.offset(index as isize), 1);
+            self.len = last;
+            result
+        }
+    }
+
+    pub fn retain<F: FnMut(&T) -> bool>(&mut self, mut f: F) {
+        let len = self.len;
+        let mut new_len = 0;
+
+        unsafe {
+            let p = self.as_mut_ptr();
+            for i in 0..len {
+                let keep = f(&*p.offset(i as isize));
+                if keep {
+                    if new_len != i {
+                        ptr::copy_nonoverlapping(p.offset(i as isize), p.offset(new_len as isize), 1);
+                    }
+                    new_len += 1;
+                } else {
+                    ptr::drop_in_place(p.offset(i as isize));
+                }
+            }
+        }
+        self.len = new_len;
+    }
+
+    /// Shortens the vector to `len`, dropping the elements at the tail.
+    /// Does nothing if `len >= self.len()`, and never touches the `FREE`/
+    /// `EMBEDDED` backing store itself (only `Drop` does that).
+    pub fn truncate(&mut self, len: usize) {
+        if len >= self.len {
+            return;
+        }
+
+        unsafe {
+            let p = self.as_mut_ptr();
+            let tail = ::std::slice::from_raw_parts_mut(p.offset(len as isize), self.len - len);
+            ptr::drop_in_place(tail);
+        }
+        self.len = len;
+    }
+
+    /// Drops all elements, keeping the backing storage (embedded or
+    /// allocated) around for reuse, exactly like `Vec::clear`.
+    pub fn clear(&mut self) {
+        self.truncate(0);
+    }
+
+    /// Moves all of `other`'s elements onto the end of `self`, leaving
+    /// `other` empty but with its backing storage intact. Takes `other` by
+    /// mutable reference rather than by value, mirroring `Vec::append`:
+    /// `other`'s own `ptr` is a `TaggedRelativePointer`, and relocating
+    /// `other` across a by-value move would invalidate it before we ever got
+    /// to read it. The elements themselves are moved bitwise.
+    pub fn extend_from_compact(&mut self, other: &mut CompactVec<T, A>) {
+        let additional = other.len;
+        self.try_reserve(additional).expect("CompactVec allocation failed");
+
+        unsafe {
+            let dest = self.as_mut_ptr().offset(self.len as isize);
+            ptr::copy_nonoverlapping(other.as_mut_ptr(), dest, additional);
+            self.len += additional;
+            other.set_len(0);
+        }
+    }
 }
 
 impl<T, A: Allocator> Drop for CompactVec<T, A> {
@@ -187,11 +324,337 @@ impl<T, A: Allocator> Compact for CompactVec<T, A> {
         self.cap * mem::size_of::<T>()
     }
 
-    unsafe fn compact_from(&mut self, source: &Self, new_dynamic_part: *mut u8) {
-        self.len = source.len;
-        self.cap = source.cap;
-        self.ptr.set(transmute(new_dynamic_part), EMBEDDED);
-        ptr::copy_nonoverlapping(source.ptr.ptr(), self.ptr.mut_ptr(), self.len);
+    unsafe fn compact(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        let len = (*source).len;
+        ptr::write(&mut (*dest).len, len);
+        ptr::write(&mut (*dest).cap, (*source).cap);
+        ptr::write(&mut (*dest)._alloc, PhantomData);
+        ptr::write(&mut (*dest).ptr, TaggedRelativePointer::null(EMBEDDED));
+        (*dest).ptr.set(transmute(new_dynamic_part), EMBEDDED);
+        ptr::copy_nonoverlapping((*source).ptr.ptr(), (*dest).ptr.mut_ptr(), len);
+    }
+}
+
+// Like `CompactVec`, but up to `N` elements live inline in the static
+// part of the struct, so small vectors (the common case for e.g. lane
+// connections) never touch the dynamic part / allocator at all. Once it
+// spills, all elements move to the allocated buffer.
+pub struct CompactSmallVec<T, const N: usize, A: Allocator = DefaultHeap> {
+    inline: [MaybeUninit<T>; N],
+    ptr: TaggedRelativePointer<T>,
+    len: usize,
+    cap: usize,
+    _alloc: PhantomData<A>
+}
+
+impl<T, const N: usize, A: Allocator> CompactSmallVec<T, N, A> {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn new() -> CompactSmallVec<T, N, A> {
+        CompactSmallVec {
+            inline: unsafe { MaybeUninit::uninit().assume_init() },
+            ptr: TaggedRelativePointer::null(EMBEDDED),
+            len: 0,
+            cap: N,
+            _alloc: PhantomData
+        }
+    }
+
+    fn spilled(&self) -> bool {
+        !self.ptr.ptr().is_null()
+    }
+
+    fn inline_ptr(&self) -> *const T {
+        self.inline.as_ptr() as *const T
+    }
+
+    fn inline_mut_ptr(&mut self) -> *mut T {
+        self.inline.as_mut_ptr() as *mut T
+    }
+
+    pub fn as_mut_ptr(&mut self) -> *mut T {
+        if self.spilled() {
+            self.ptr.mut_ptr()
+        } else {
+            self.inline_mut_ptr()
+        }
+    }
+
+    fn maybe_drop(&mut self) {
+        if self.spilled() {
+            if self.ptr.is_tagged() == FREE {
+                unsafe {
+                    ptr::drop_in_place(&mut self[..]);
+                    A::deallocate(self.ptr.mut_ptr(), self.cap);
+                }
+            }
+        } else {
+            // Inline storage is always genuinely owned by this value (there's
+            // no FREE/EMBEDDED tag to consult for it, unlike the spilled
+            // pointer), so its elements must always be destructed here.
+            unsafe {
+                ptr::drop_in_place(&mut self[..]);
+            }
+        }
+    }
+
+    /// Ensures room for `additional` more elements, growing through `A`
+    /// (amortized doubling, capped at `max(len + additional, cap * 2)`)
+    /// instead of aborting the process on allocation failure, exactly like
+    /// `CompactVec::try_reserve`. Once `len` would exceed the inline
+    /// capacity `N` this spills into a real `A`-backed allocation.
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(), TryReserveError> {
+        if self.cap - self.len >= additional {
+            return Ok(());
+        }
+
+        let required_cap = self.len.checked_add(additional)
+            .ok_or(TryReserveError::CapacityOverflow)?;
+        let doubled_cap = self.cap.checked_mul(2)
+            .ok_or(TryReserveError::CapacityOverflow)?;
+        let new_cap = cmp::max(required_cap, doubled_cap);
+
+        let new_size_bytes = new_cap.checked_mul(mem::size_of::<T>())
+            .ok_or(TryReserveError::CapacityOverflow)?;
+        if new_size_bytes > isize::MAX as usize {
+            return Err(TryReserveError::CapacityOverflow);
+        }
+        let layout = Layout::array::<T>(new_cap)
+            .map_err(|_| TryReserveError::CapacityOverflow)?;
+
+        let new_ptr = A::allocate::<T>(new_cap);
+        if new_ptr.is_null() {
+            return Err(TryReserveError::AllocError { layout: layout });
+        }
+
+        unsafe {
+            ptr::copy_nonoverlapping(self.as_mut_ptr(), new_ptr, self.len);
+            if self.spilled() && self.ptr.is_tagged() == FREE {
+                A::deallocate(self.ptr.mut_ptr(), self.cap);
+            }
+        }
+        self.ptr.set(new_ptr, FREE);
+        self.cap = new_cap;
+        Ok(())
+    }
+
+    pub fn try_push(&mut self, value: T) -> Result<(), (T, TryReserveError)> {
+        if let Err(err) = self.try_reserve(1) {
+            return Err((value, err));
+        }
+
+        unsafe {
+            let end = self.as_mut_ptr().offset(self.len as isize);
+            ptr::write(end, value);
+        }
+        self.len += 1;
+        Ok(())
+    }
+
+    pub fn push(&mut self, value: T) {
+        self.try_push(value).unwrap_or_else(|(_, err)| {
+            panic!("CompactSmallVec allocation failed: {:?}", err)
+        });
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            None
+        } else {
+            unsafe {
+                self.len -= 1;
+                Some(ptr::read(self.get_unchecked(self.len())))
+            }
+        }
+    }
+}
+
+impl<T, const N: usize, A: Allocator> Drop for CompactSmallVec<T, N, A> {
+    fn drop(&mut self) {
+        self.maybe_drop();
+    }
+}
+
+impl<T, const N: usize, A: Allocator> Deref for CompactSmallVec<T, N, A> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        let ptr = if self.spilled() {self.ptr.ptr()} else {self.inline_ptr()};
+        unsafe {
+            ::std::slice::from_raw_parts(ptr, self.len)
+        }
+    }
+}
+
+impl<T, const N: usize, A: Allocator> DerefMut for CompactSmallVec<T, N, A> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        let len = self.len;
+        let ptr = self.as_mut_ptr();
+        unsafe {
+            ::std::slice::from_raw_parts_mut(ptr, len)
+        }
+    }
+}
+
+impl<'a, T, const N: usize, A: Allocator> IntoIterator for &'a CompactSmallVec<T, N, A> {
+    type Item = &'a T;
+    type IntoIter = ::std::slice::Iter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.deref().into_iter()
+    }
+}
+
+impl<'a, T, const N: usize, A: Allocator> IntoIterator for &'a mut CompactSmallVec<T, N, A> {
+    type Item = &'a mut T;
+    type IntoIter = ::std::slice::IterMut<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.deref_mut().into_iter()
+    }
+}
+
+impl<T, const N: usize, A: Allocator> Compact for CompactSmallVec<T, N, A> {
+    fn is_still_compact(&self) -> bool {
+        !self.spilled() || self.ptr.is_tagged() == EMBEDDED
+    }
+
+    fn dynamic_size_bytes(&self) -> usize {
+        if self.spilled() {self.cap * mem::size_of::<T>()} else {0}
+    }
+
+    unsafe fn compact(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        let len = (*source).len;
+        ptr::write(&mut (*dest).len, len);
+        ptr::write(&mut (*dest).cap, (*source).cap);
+        ptr::write(&mut (*dest)._alloc, PhantomData);
+        ptr::write(&mut (*dest).ptr, TaggedRelativePointer::null(EMBEDDED));
+        if (*source).spilled() {
+            (*dest).ptr.set(transmute(new_dynamic_part), EMBEDDED);
+            ptr::copy_nonoverlapping((*source).ptr.ptr(), (*dest).ptr.mut_ptr(), len);
+        } else {
+            ptr::copy_nonoverlapping((*source).inline_ptr(), (*dest).inline_mut_ptr(), len);
+        }
+    }
+}
+
+pub type TypeTag = ::std::any::TypeId;
+
+/// Maps a `TypeTag` to a function that turns a pointer at a `CompactDynVec`
+/// entry's byte offset back into a caller-chosen view `R` (e.g. `&dyn Trait`
+/// or an enum). Entries with no registered reconstructor can't be iterated.
+pub struct DynReconstructTable<R> {
+    entries: Vec<(TypeTag, unsafe fn(*const u8) -> R)>
+}
+
+impl<R> DynReconstructTable<R> {
+    pub fn new() -> DynReconstructTable<R> {
+        DynReconstructTable {entries: Vec::new()}
+    }
+
+    pub fn register<U: Compact + 'static>(&mut self, reconstruct: unsafe fn(*const u8) -> R) {
+        self.entries.push((TypeTag::of::<U>(), reconstruct));
+    }
+
+    fn lookup(&self, tag: TypeTag) -> Option<unsafe fn(*const u8) -> R> {
+        self.entries.iter().find(|&&(t, _)| t == tag).map(|&(_, f)| f)
+    }
+}
+
+/// Packs values of differing, independently-`Compact` types back-to-back in
+/// one byte blob, alongside an index of `(type tag, offset, len)` triples.
+/// Useful for compact event logs / command queues where many distinct
+/// command types share one contiguous buffer. Dropping a `CompactDynVec`
+/// only frees the raw byte blob; it does not run the destructors of the
+/// individual values packed inside it.
+pub struct CompactDynVec<A: Allocator = DefaultHeap> {
+    index: CompactVec<(TypeTag, u32, u32), A>,
+    bytes: CompactVec<u8, A>
+}
+
+impl<A: Allocator> CompactDynVec<A> {
+    pub fn new() -> CompactDynVec<A> {
+        CompactDynVec {
+            index: CompactVec::new(),
+            bytes: CompactVec::new()
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    /// Compacts `v` into the next free, correctly-aligned slot of the byte
+    /// blob and appends an index entry for it. Takes `v` by value, just like
+    /// `CompactVec::push(&mut self, value: T)`: a freshly-constructed `v`
+    /// holds plain, absolute (`FREE`-tagged) pointers, so moving it here is
+    /// no different to moving it into any other owning collection. Once its
+    /// bytes are compacted into the blob, `v` itself is dropped normally,
+    /// freeing whatever backing allocation it held.
+    pub fn push<U: Compact + 'static>(&mut self, v: U) {
+        let size = v.total_size_bytes();
+        // Pad up to `U`'s alignment; relies on the allocator returning a
+        // base pointer aligned at least as strictly as any pushed type.
+        let align = mem::align_of::<U>();
+        let base = self.bytes.len();
+        let offset = (base + align - 1) / align * align;
+
+        self.bytes.try_reserve(offset + size - base).expect("CompactDynVec allocation failed");
+        unsafe {
+            let dest = self.bytes.as_mut_ptr().offset(offset as isize) as *mut U;
+            assert_eq!(dest as usize % align, 0,
+                "CompactDynVec's byte blob base is not aligned enough to host a type \
+                 with alignment {} at this offset", align);
+            let new_dynamic_part = dest.offset(1) as *mut u8;
+            U::compact(&v as *const U as *mut U, dest, new_dynamic_part);
+            self.bytes.set_len(offset + size);
+        }
+
+        self.index.push((TypeTag::of::<U>(), offset as u32, size as u32));
+    }
+
+    pub fn get<U: Compact + 'static>(&self, i: usize) -> Option<&U> {
+        if i >= self.len() {
+            return None;
+        }
+        let (tag, offset, _len) = self.index[i];
+        if tag != TypeTag::of::<U>() {
+            return None;
+        }
+        unsafe {
+            Some(&*(self.bytes.as_ptr().offset(offset as isize) as *const U))
+        }
+    }
+
+    pub fn iter<'a, R>(&'a self, table: &'a DynReconstructTable<R>) -> impl Iterator<Item = R> + 'a {
+        self.index.iter().map(move |&(tag, offset, _len)| {
+            let reconstruct = table.lookup(tag)
+                .expect("no reconstructor registered for this CompactDynVec's type tag");
+            unsafe {
+                reconstruct(self.bytes.as_ptr().offset(offset as isize))
+            }
+        })
+    }
+}
+
+impl<A: Allocator> Compact for CompactDynVec<A> {
+    fn is_still_compact(&self) -> bool {
+        self.index.is_still_compact() && self.bytes.is_still_compact()
+    }
+
+    fn dynamic_size_bytes(&self) -> usize {
+        self.index.dynamic_size_bytes() + self.bytes.dynamic_size_bytes()
+    }
+
+    unsafe fn compact(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        let mut offset: isize = 0;
+        Compact::compact(ptr::addr_of_mut!((*source).index), ptr::addr_of_mut!((*dest).index),
+                          new_dynamic_part.offset(offset));
+        offset += (*source).index.dynamic_size_bytes() as isize;
+        Compact::compact(ptr::addr_of_mut!((*source).bytes), ptr::addr_of_mut!((*dest).bytes),
+                          new_dynamic_part.offset(offset));
     }
 }
 
@@ -201,8 +664,8 @@ macro_rules! plain {
             impl Compact for $trivial_type {
                 fn is_still_compact(&self) -> bool {true}
                 fn dynamic_size_bytes(&self) -> usize {0}
-                unsafe fn compact_from(&mut self, source: &Self, _new_dynamic_part: *mut u8) {
-                    *self = *source;
+                unsafe fn compact(source: *mut Self, dest: *mut Self, _new_dynamic_part: *mut u8) {
+                    ptr::copy_nonoverlapping(source, dest, 1);
                 }
             }
         )*
@@ -225,10 +688,10 @@ macro_rules! derive_compact {
                 derive_dynamic_size_bytes!(self, $fields)
             }
 
-            unsafe fn compact_from(&mut self, source: &Self, new_dynamic_part: *mut u8) {
+            unsafe fn compact(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
                 #![allow(unused_assignments)]
                 let mut offset: isize = 0;
-                derive_compact_from!(self, source, new_dynamic_part, offset, $fields);
+                derive_compact_from!(source, dest, new_dynamic_part, offset, $fields);
             }
         }
     }
@@ -260,11 +723,53 @@ macro_rules! derive_compact {
 //     }
 // }
 
+// Until the overlap above is resolved, wrap the payload in a newtype instead.
+pub struct CompactOption<T: Compact>(pub Option<T>);
+
+impl<T: Compact> Deref for CompactOption<T> {
+    type Target = Option<T>;
+
+    fn deref(&self) -> &Option<T> {
+        &self.0
+    }
+}
+
+impl<T: Compact> DerefMut for CompactOption<T> {
+    fn deref_mut(&mut self) -> &mut Option<T> {
+        &mut self.0
+    }
+}
+
+impl<T: Compact> Compact for CompactOption<T> {
+    fn is_still_compact(&self) -> bool {
+        match self.0 {
+            None => true,
+            Some(ref inner) => inner.is_still_compact()
+        }
+    }
+
+    fn dynamic_size_bytes(&self) -> usize {
+        match self.0 {
+            None => 0,
+            Some(ref inner) => inner.dynamic_size_bytes()
+        }
+    }
+
+    unsafe fn compact(source: *mut Self, dest: *mut Self, new_dynamic_part: *mut u8) {
+        ptr::copy_nonoverlapping(source, dest, 1);
+        if (*source).0.is_some() && (*dest).0.is_some() {
+            let source_inner = (*source).0.as_mut().unwrap() as *mut T;
+            let dest_inner = (*dest).0.as_mut().unwrap() as *mut T;
+            T::compact(source_inner, dest_inner, new_dynamic_part);
+        }
+    }
+}
+
 impl<T: Copy> Compact for T {
     fn is_still_compact(&self) -> bool {true}
     fn dynamic_size_bytes(&self) -> usize {0}
-    unsafe fn compact_from(&mut self, source: &Self, _new_dynamic_part: *mut u8) {
-        *self = *source;
+    unsafe fn compact(source: *mut Self, dest: *mut Self, _new_dynamic_part: *mut u8) {
+        ptr::copy_nonoverlapping(source, dest, 1);
     }
 }
 
@@ -293,10 +798,159 @@ macro_rules! derive_dynamic_size_bytes {
 
 #[macro_export]
 macro_rules! derive_compact_from {
-    ($the_self:ident, $source:ident, $new_dynamic_part:ident, $offset:ident, {$($field:ident: $field_type:ty),*}) => {
+    ($source:ident, $dest:ident, $new_dynamic_part:ident, $offset:ident, {$($field:ident: $field_type:ty),*}) => {
         $(
-            $the_self.$field.compact_from(&$source.$field, $new_dynamic_part.offset($offset));
-            $offset += $source.$field.dynamic_size_bytes() as isize;
+            Compact::compact(
+                ::std::ptr::addr_of_mut!((*$source).$field),
+                ::std::ptr::addr_of_mut!((*$dest).$field),
+                $new_dynamic_part.offset($offset)
+            );
+            $offset += (*$source).$field.dynamic_size_bytes() as isize;
         )*
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn remove_and_swap_remove_at_the_last_index() {
+        let mut v: CompactVec<u32> = CompactVec::new();
+        for i in 0..5 { v.push(i); }
+
+        assert_eq!(v.remove(0), 0);
+        assert_eq!(&v[..], &[1, 2, 3, 4]);
+        assert_eq!(v.remove(v.len() - 1), 4);
+        assert_eq!(&v[..], &[1, 2, 3]);
+
+        assert_eq!(v.swap_remove(0), 1);
+        assert_eq!(&v[..], &[3, 2]);
+        assert_eq!(v.swap_remove(v.len() - 1), 2);
+        assert_eq!(&v[..], &[3]);
+    }
+
+    #[test]
+    fn retain_on_empty_and_drop_all() {
+        let mut v: CompactVec<u32> = CompactVec::new();
+        v.retain(|_| true);
+        assert_eq!(v.len(), 0);
+
+        for i in 0..4 { v.push(i); }
+        v.retain(|_| false);
+        assert_eq!(v.len(), 0);
+
+        for i in 0..4 { v.push(i); }
+        v.retain(|&x| x % 2 == 0);
+        assert_eq!(&v[..], &[0, 2]);
+    }
+
+    #[test]
+    fn truncate_past_len_is_a_no_op_and_clear_empties() {
+        let mut v: CompactVec<u32> = CompactVec::new();
+        for i in 0..4 { v.push(i); }
+
+        v.truncate(10);
+        assert_eq!(&v[..], &[0, 1, 2, 3]);
+        v.truncate(2);
+        assert_eq!(&v[..], &[0, 1]);
+        v.clear();
+        assert_eq!(v.len(), 0);
+    }
+
+    #[test]
+    fn extend_from_compact_drains_the_source() {
+        let mut a: CompactVec<u32> = CompactVec::new();
+        a.push(1);
+        a.push(2);
+        let mut b: CompactVec<u32> = CompactVec::new();
+        b.push(3);
+        b.push(4);
+        b.push(5);
+
+        a.extend_from_compact(&mut b);
+        assert_eq!(&a[..], &[1, 2, 3, 4, 5]);
+        assert_eq!(b.len(), 0);
+    }
+
+    #[test]
+    fn try_reserve_reports_capacity_overflow_instead_of_aborting() {
+        let mut v: CompactVec<u32> = CompactVec::new();
+        assert_eq!(v.try_reserve(usize::MAX).unwrap_err(), TryReserveError::CapacityOverflow);
+    }
+
+    #[test]
+    fn small_vec_spills_past_inline_capacity() {
+        let mut v: CompactSmallVec<u32, 2> = CompactSmallVec::new();
+        v.push(1);
+        v.push(2);
+        assert!(!v.spilled());
+        assert_eq!(&v[..], &[1, 2]);
+
+        v.push(3);
+        assert!(v.spilled());
+        assert_eq!(&v[..], &[1, 2, 3]);
+
+        v.push(4);
+        assert_eq!(&v[..], &[1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn small_vec_try_reserve_reports_capacity_overflow_instead_of_aborting() {
+        let mut v: CompactSmallVec<u32, 2> = CompactSmallVec::new();
+        assert_eq!(v.try_reserve(usize::MAX).unwrap_err(), TryReserveError::CapacityOverflow);
+    }
+
+    #[test]
+    fn small_vec_drops_inline_elements_without_ever_spilling() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        struct Counted;
+        impl Drop for Counted {
+            fn drop(&mut self) { DROPS.fetch_add(1, Ordering::SeqCst); }
+        }
+
+        {
+            let mut v: CompactSmallVec<Counted, 4> = CompactSmallVec::new();
+            v.push(Counted);
+            v.push(Counted);
+        }
+        assert_eq!(DROPS.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn compact_vec_drops_each_element_exactly_once_across_a_grow() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        struct Counted(#[allow(dead_code)] Box<u32>);
+        impl Drop for Counted {
+            fn drop(&mut self) { DROPS.fetch_add(1, Ordering::SeqCst); }
+        }
+
+        {
+            let mut v: CompactVec<Counted> = CompactVec::new();
+            v.push(Counted(Box::new(1))); // cap 0 -> 1
+            v.push(Counted(Box::new(2))); // cap 1 -> 2, forces a grow
+            v.push(Counted(Box::new(3))); // cap 2 -> 4, forces another grow
+            assert_eq!(v.len(), 3);
+        }
+        assert_eq!(DROPS.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn dyn_vec_push_get_and_out_of_range() {
+        let mut inner: CompactVec<u32> = CompactVec::new();
+        inner.push(1);
+        inner.push(2);
+        inner.push(3);
+
+        let mut dv: CompactDynVec = CompactDynVec::new();
+        dv.push(inner);
+        dv.push(42u64);
+
+        assert_eq!(&dv.get::<CompactVec<u32>>(0).unwrap()[..], &[1, 2, 3]);
+        assert_eq!(*dv.get::<u64>(1).unwrap(), 42u64);
+        assert!(dv.get::<u64>(0).is_none());
+        assert!(dv.get::<u64>(99).is_none());
+    }
 }
\ No newline at end of file